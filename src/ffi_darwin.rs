@@ -21,3 +21,4 @@ pub static F_WRLCK: c_short  = 3;
 pub static F_SETFD: c_int    = 2;
 pub static F_GETLK: c_int    = 7;
 pub static F_SETLK: c_int    = 8;
+pub static F_SETLKW: c_int   = 9;
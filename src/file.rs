@@ -0,0 +1,74 @@
+//! Fallback backend for targets that have neither POSIX `fcntl`/`flock` nor
+//! the Win32 locking API (for example `wasm32-unknown-unknown`).
+//!
+//! It mirrors the `File` surface of the `file_posix`/`file_windows` backends
+//! so that downstream crates which merely *depend* on pidfile still compile
+//! for such targets; every operation fails at runtime with
+//! `io::ErrorKind::Unsupported` rather than failing to link.
+
+#![allow(dead_code)]
+
+use std::io;
+use libc::pid_t;
+use {LockMode, LockStyle};
+
+/// Lock outcome. Never constructed on this target — every operation fails
+/// before an outcome can be produced — but the variants must exist to match
+/// the other backends' `File` surface.
+pub enum LockOutcome {
+    Acquired,
+    Conflict,
+    TimedOut,
+}
+
+pub struct Record {
+    pub pid: pid_t,
+    pub created: u64,
+}
+
+pub struct File {
+    // Never constructed: `open` always fails on this target.
+    _private: (),
+}
+
+fn unsupported<T>() -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "pidfile locking is not supported on this target"))
+}
+
+impl File {
+    pub fn open(_path: &::std::path::Path, _create: bool, _write: bool, _mode: u32) -> io::Result<File> {
+        unsupported()
+    }
+
+    pub fn truncate(&mut self) -> io::Result<()> {
+        unsupported()
+    }
+
+    pub fn lock(&mut self, _mode: LockMode, _style: LockStyle) -> io::Result<LockOutcome> {
+        unsupported()
+    }
+
+    pub fn check(&mut self, _style: LockStyle) -> io::Result<pid_t> {
+        unsupported()
+    }
+
+    pub fn write(&mut self, _pid: pid_t) -> io::Result<()> {
+        unsupported()
+    }
+
+    pub fn recorded_owner(&mut self) -> io::Result<Option<Record>> {
+        unsupported()
+    }
+}
+
+pub fn process_start_time(_pid: pid_t) -> io::Result<u64> {
+    Ok(0)
+}
+
+pub fn process_created(_pid: pid_t) -> io::Result<u64> {
+    Ok(0)
+}
+
+pub fn process_is_current(_pid: pid_t, _created: u64) -> bool {
+    false
+}
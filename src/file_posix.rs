@@ -4,9 +4,14 @@ use std::{io, mem};
 use std::io::Write;
 use std::path::Path;
 use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::Duration;
 use libc;
 use libc::{
     c_void, c_int, c_short, pid_t, mode_t, size_t,
+    itimerval, timeval, sigaction, sighandler_t,
+    ITIMER_REAL, SIGALRM, ESRCH,
+    LOCK_EX, LOCK_NB, LOCK_UN,
     O_CREAT, O_WRONLY, SEEK_SET, EINTR, EACCES, EAGAIN
 };
 use nix;
@@ -15,8 +20,27 @@ use nix::fcntl::{self, open};
 use nix::sys::stat;
 use ffi::{
     flock, O_SYNC, F_SETFD, F_GETLK,
-    F_SETLK, F_WRLCK, F_UNLCK, FD_CLOEXEC
+    F_SETLK, F_SETLKW, F_WRLCK, F_UNLCK, FD_CLOEXEC
 };
+use {LockMode, LockStyle};
+
+/// Outcome of an attempt to take the advisory lock.
+pub enum LockOutcome {
+    /// The lock was acquired.
+    Acquired,
+    /// The lock is held by another process.
+    Conflict,
+    /// The bounded wait elapsed before the lock could be taken.
+    TimedOut,
+}
+
+// Set from the `SIGALRM` handler so the blocked `fcntl(F_SETLKW)` can tell a
+// timeout interrupt apart from a spurious one.
+static ALARM_FIRED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_alarm(_: c_int) {
+    ALARM_FIRED.store(true, Ordering::SeqCst);
+}
 
 pub struct File {
     fd: c_int
@@ -92,12 +116,29 @@ unsafe fn setlk(fd: c_int, fl: &flock) -> c_int {
     ret
 }
 
+// Non-blocking `flock`, mirroring `setlk`: returns 1 on contention instead of
+// a hard error so the caller can treat it as a conflict.
+unsafe fn flock_nb(fd: c_int) -> c_int {
+    let ret = libc::flock(fd, LOCK_EX | LOCK_NB);
+
+    if ret < 0 {
+        match errno() as c_int {
+            EACCES | EAGAIN => return 1,
+            _ => {}
+        }
+    }
+
+    ret
+}
+
 impl File {
     pub fn open(path: &Path, create: bool, write: bool, mode: u32) -> io::Result<File> {
         let mut flags = fcntl::O_SYNC;
 
-        if create { flags = flags | fcntl::O_CREAT;  }
-        if write  { flags = flags | fcntl::O_WRONLY; }
+        if create { flags = flags | fcntl::O_CREAT; }
+        // `O_RDWR` rather than `O_WRONLY` so the recorded owner can be read
+        // back off the same descriptor when reclaiming a stale pidfile.
+        if write  { flags = flags | fcntl::O_RDWR; }
 
         // Open the file descriptor
         let fd = nix_check!(open(path, flags, stat::Mode::from_bits(mode as mode_t).unwrap()));
@@ -113,49 +154,218 @@ impl File {
         Ok(())
     }
 
-    pub fn lock(&mut self) -> io::Result<bool> {
+    pub fn lock(&mut self, mode: LockMode, style: LockStyle) -> io::Result<LockOutcome> {
+        match style {
+            LockStyle::Fcntl => self.fcntl_lock(mode),
+            LockStyle::Flock => self.flock_lock(mode),
+        }
+    }
+
+    fn fcntl_lock(&mut self, mode: LockMode) -> io::Result<LockOutcome> {
         let mut fl: flock = unsafe { mem::zeroed() };
 
         fl.l_type = F_WRLCK;
         fl.l_whence = SEEK_SET as c_short;
 
-        let ret = check!(setlk(self.fd, &fl));
+        match mode {
+            LockMode::NonBlocking => {
+                let ret = check!(setlk(self.fd, &fl));
 
-        Ok(ret == 0)
+                Ok(if ret == 0 { LockOutcome::Acquired } else { LockOutcome::Conflict })
+            }
+            LockMode::Blocking => self.setlkw(&fl),
+            LockMode::Timeout(dur) => self.setlkw_timeout(&fl, dur),
+        }
     }
 
-    pub fn check(&mut self) -> io::Result<pid_t> {
-        let mut fl: flock = unsafe { mem::zeroed() };
+    // `flock(2)` has no `F_SETLKW`/timeout distinction of its own: `LOCK_EX`
+    // blocks and `LOCK_EX | LOCK_NB` fails fast. The bounded wait reuses the
+    // same `SIGALRM` trick as the fcntl path.
+    fn flock_lock(&mut self, mode: LockMode) -> io::Result<LockOutcome> {
+        match mode {
+            LockMode::NonBlocking => {
+                let ret = check!(flock_nb(self.fd));
 
-        fl.l_type = F_WRLCK;
-        fl.l_whence = SEEK_SET as c_short;
+                Ok(if ret == 0 { LockOutcome::Acquired } else { LockOutcome::Conflict })
+            }
+            LockMode::Blocking => {
+                check!(libc::flock(self.fd, LOCK_EX));
+                Ok(LockOutcome::Acquired)
+            }
+            LockMode::Timeout(dur) => {
+                if dur == Duration::new(0, 0) {
+                    let ret = check!(flock_nb(self.fd));
+                    return Ok(if ret == 0 { LockOutcome::Acquired } else { LockOutcome::TimedOut });
+                }
+
+                ALARM_FIRED.store(false, Ordering::SeqCst);
+
+                let old = try!(install_alarm_handler());
+                try!(arm_timer(dur));
+
+                let outcome = loop {
+                    // The timer may fire before we reach `flock`; honour it up
+                    // front so a delivered-but-missed `SIGALRM` can't leave us
+                    // blocked forever.
+                    if ALARM_FIRED.load(Ordering::SeqCst) {
+                        break Ok(LockOutcome::TimedOut);
+                    }
 
-        check!(libc::fcntl(self.fd, F_GETLK, &fl as *const flock));
+                    let ret = unsafe { libc::flock(self.fd, LOCK_EX) };
 
-        if fl.l_type == F_UNLCK {
-            Ok(0)
+                    if ret < 0 {
+                        let err = errno() as c_int;
+
+                        if err == EINTR {
+                            if ALARM_FIRED.load(Ordering::SeqCst) {
+                                break Ok(LockOutcome::TimedOut);
+                            }
+
+                            continue;
+                        }
+
+                        break Err(from_raw_os_error(err));
+                    }
+
+                    break Ok(LockOutcome::Acquired);
+                };
+
+                let _ = disarm_timer();
+                restore_alarm_handler(&old);
+
+                outcome
+            }
         }
-        else {
-            Ok(fl.l_pid)
+    }
+
+    // Wait indefinitely for the lock. `F_SETLKW` blocks until the holder
+    // releases it; a spurious `EINTR` simply restarts the wait.
+    fn setlkw(&mut self, fl: &flock) -> io::Result<LockOutcome> {
+        loop {
+            let ret = unsafe { libc::fcntl(self.fd, F_SETLKW, fl as *const flock) };
+
+            if ret < 0 {
+                let err = errno() as c_int;
+
+                if err == EINTR {
+                    continue;
+                }
+
+                return Err(from_raw_os_error(err));
+            }
+
+            return Ok(LockOutcome::Acquired);
         }
     }
 
-    pub fn write(&mut self, pid: pid_t) -> io::Result<()> {
-        let mut buf: [u8; 20] = unsafe { mem::zeroed() };
+    // Wait for the lock but give up after `dur`. `fcntl` cannot take a timeout
+    // of its own, so we arm a one-shot `SIGALRM` whose handler sets
+    // `ALARM_FIRED`; the resulting `EINTR` is then distinguishable from a
+    // spurious one and reported as a timeout rather than looping forever.
+    fn setlkw_timeout(&mut self, fl: &flock, dur: Duration) -> io::Result<LockOutcome> {
+        // A zero timeout does not arm the timer at all: it fails fast with a
+        // single non-blocking attempt.
+        if dur == Duration::new(0, 0) {
+            let ret = check!(setlk(self.fd, fl));
+            return Ok(if ret == 0 { LockOutcome::Acquired } else { LockOutcome::TimedOut });
+        }
+
+        ALARM_FIRED.store(false, Ordering::SeqCst);
+
+        let old = try!(install_alarm_handler());
+        try!(arm_timer(dur));
+
+        let outcome = loop {
+            // The timer may fire between `arm_timer` and the kernel entering
+            // `fcntl`; honour an already-fired alarm up front so the wakeup
+            // can't be lost and leave us blocked forever.
+            if ALARM_FIRED.load(Ordering::SeqCst) {
+                break Ok(LockOutcome::TimedOut);
+            }
 
-        let len = {
-            let mut reader = io::Cursor::new(&mut buf[..]);
+            let ret = unsafe { libc::fcntl(self.fd, F_SETLKW, fl as *const flock) };
 
-            try!(write!(&mut reader, "{}\n", pid));
-            reader.position()
+            if ret < 0 {
+                let err = errno() as c_int;
+
+                if err == EINTR {
+                    if ALARM_FIRED.load(Ordering::SeqCst) {
+                        break Ok(LockOutcome::TimedOut);
+                    }
+
+                    continue;
+                }
+
+                break Err(from_raw_os_error(err));
+            }
+
+            break Ok(LockOutcome::Acquired);
         };
 
+        // Disarm the timer and restore the caller's handler regardless of how
+        // the wait ended.
+        let _ = disarm_timer();
+        restore_alarm_handler(&old);
+
+        outcome
+    }
+
+    pub fn check(&mut self, style: LockStyle) -> io::Result<pid_t> {
+        match style {
+            LockStyle::Fcntl => {
+                let mut fl: flock = unsafe { mem::zeroed() };
+
+                fl.l_type = F_WRLCK;
+                fl.l_whence = SEEK_SET as c_short;
+
+                check!(libc::fcntl(self.fd, F_GETLK, &fl as *const flock));
+
+                if fl.l_type == F_UNLCK {
+                    Ok(0)
+                }
+                else {
+                    Ok(fl.l_pid)
+                }
+            }
+            LockStyle::Flock => {
+                // `flock` has no `F_GETLK` to report the holder, so probe with
+                // a non-blocking lock and, if held, read the pid back out of
+                // the file contents.
+                let ret = check!(flock_nb(self.fd));
+
+                if ret == 0 {
+                    check!(libc::flock(self.fd, LOCK_UN));
+                    return Ok(0);
+                }
+
+                Ok(try!(self.recorded_owner()).map(|r| r.pid).unwrap_or(0))
+            }
+        }
+    }
+
+    pub fn write(&mut self, pid: pid_t) -> io::Result<()> {
+        // Line 2 is the raw start time (on Linux clock ticks since boot; on
+        // BSD/Darwin an absolute timestamp in microseconds), kept for
+        // reconstructibility. Line 3 is the absolute instant the process began
+        // in seconds (on Linux the boot time plus the start offset), which
+        // unlike a raw boot offset survives a reboot + pid recycle.
+        let start = process_start_time(pid).unwrap_or(0);
+        let created = process_created(pid).unwrap_or(0);
+        let buf = format!("{}\n{}\n{}\n", pid, start, created).into_bytes();
+
+        // `recorded_owner`/`check` leave the offset mid-file, and `truncate`
+        // (`ftruncate`) does not rewind it. Seek back to the start so a rewrite
+        // during reclamation lands at offset 0 instead of producing a sparse
+        // file with leading NULs. (The Windows backend rewinds via
+        // `SetFilePointer`.)
+        check!(libc::lseek(self.fd, 0, SEEK_SET));
+
         let mut pos = 0;
 
-        while pos < len {
+        while pos < buf.len() {
             let ptr = unsafe { buf.as_ptr().offset(pos as isize) };
-            let ret = check!(libc::write(self.fd, ptr as *const c_void, (len - pos) as size_t));
-            pos += ret as u64;
+            let ret = check!(libc::write(self.fd, ptr as *const c_void, (buf.len() - pos) as size_t));
+            pos += ret as usize;
         }
 
         Ok(())
@@ -164,6 +374,157 @@ impl File {
     pub fn stat(&self) -> nix::Result<stat::FileStat> {
         stat::fstat(self.fd)
     }
+
+    pub fn recorded_owner(&mut self) -> io::Result<Option<Record>> {
+        unsafe { libc::lseek(self.fd, 0, SEEK_SET); }
+
+        let mut buf = [0u8; 64];
+        let n = check!(libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t));
+
+        let s = String::from_utf8_lossy(&buf[..n as usize]);
+        let mut lines = s.lines();
+
+        let pid = match lines.next().and_then(|l| l.trim().parse().ok()) {
+            Some(pid) if pid > 0 => pid,
+            _ => return Ok(None),
+        };
+
+        // Line 2 is the raw start time, kept on disk for reconstructibility
+        // but not needed here; line 3 is the absolute instant we compare on.
+        let _offset = lines.next();
+        let created = lines.next().and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+
+        Ok(Some(Record { pid: pid, created: created }))
+    }
+}
+
+pub struct Record {
+    pub pid: pid_t,
+    pub created: u64,
+}
+
+/// Reports whether `pid` names a live process whose absolute start instant
+/// still matches `created`. Comparing the boot-relative instant rather than
+/// the bare pid (or the raw boot offset) defeats the classic reboot + pid
+/// recycle false positive: after a reboot the same offset maps to a different
+/// wall-clock instant.
+pub fn process_is_current(pid: pid_t, created: u64) -> bool {
+    process_exists(pid) && process_created(pid).unwrap_or(0) == created
+}
+
+fn process_exists(pid: pid_t) -> bool {
+    // `kill(pid, 0)` performs the permission/existence checks without
+    // delivering a signal; `ESRCH` is the only errno that means "no such
+    // process".
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+
+    errno() as c_int != ESRCH
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_start_time(pid: pid_t) -> io::Result<u64> {
+    use std::fs;
+
+    // Field 22 of /proc/<pid>/stat is the process start time in clock ticks
+    // since boot. The comm field (2) is parenthesised and may itself contain
+    // spaces or parentheses, so anchor the split on the final ')'.
+    let stat = try!(fs::read_to_string(format!("/proc/{}/stat", pid)));
+
+    let rest = match stat.rfind(')') {
+        Some(idx) => &stat[idx + 1..],
+        None => return Ok(0),
+    };
+
+    Ok(rest.split_whitespace().nth(19)
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(0))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios",
+          target_os = "freebsd", target_os = "dragonfly",
+          target_os = "openbsd", target_os = "netbsd"))]
+pub fn process_start_time(pid: pid_t) -> io::Result<u64> {
+    use std::mem;
+
+    // Fetch the process's `kinfo_proc` via sysctl and read its start time.
+    let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PID, pid];
+    let mut info: libc::kinfo_proc = unsafe { mem::zeroed() };
+    let mut size = mem::size_of::<libc::kinfo_proc>() as size_t;
+
+    let ret = unsafe {
+        libc::sysctl(mib.as_mut_ptr(), mib.len() as u32,
+                     &mut info as *mut _ as *mut c_void, &mut size,
+                     ::std::ptr::null_mut(), 0)
+    };
+
+    if ret < 0 {
+        return Err(from_raw_os_error(errno() as c_int));
+    }
+
+    let tv = info.kp_proc.p_starttime;
+    Ok(tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64)
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "dragonfly",
+              target_os = "openbsd", target_os = "netbsd")))]
+pub fn process_start_time(_pid: pid_t) -> io::Result<u64> {
+    Ok(0)
+}
+
+/// The absolute instant (seconds since the epoch) at which `pid` started.
+///
+/// On Linux the per-process start time is only a boot offset, so it is
+/// anchored against the system boot time; on the BSD/Darwin path the
+/// `kinfo_proc` start time is already an absolute timestamp.
+#[cfg(target_os = "linux")]
+pub fn process_created(pid: pid_t) -> io::Result<u64> {
+    let ticks = try!(process_start_time(pid));
+    let boot = try!(boot_time());
+
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return Ok(0);
+    }
+
+    Ok(boot + ticks / hz as u64)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios",
+          target_os = "freebsd", target_os = "dragonfly",
+          target_os = "openbsd", target_os = "netbsd"))]
+pub fn process_created(pid: pid_t) -> io::Result<u64> {
+    // `p_starttime` is already an absolute instant (microseconds); reduce it
+    // to whole seconds to match the Linux resolution.
+    Ok(try!(process_start_time(pid)) / 1_000_000)
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "dragonfly",
+              target_os = "openbsd", target_os = "netbsd")))]
+pub fn process_created(_pid: pid_t) -> io::Result<u64> {
+    Ok(0)
+}
+
+// System boot time in seconds since the epoch, read from the `btime` field of
+// /proc/stat.
+#[cfg(target_os = "linux")]
+fn boot_time() -> io::Result<u64> {
+    use std::fs;
+
+    let stat = try!(fs::read_to_string("/proc/stat"));
+
+    for line in stat.lines() {
+        if line.starts_with("btime ") {
+            return Ok(line["btime ".len()..].trim().parse().unwrap_or(0));
+        }
+    }
+
+    Ok(0)
 }
 
 impl Drop for File {
@@ -173,6 +534,65 @@ impl Drop for File {
     }
 }
 
+// Install our `SIGALRM` handler, returning the caller's previous disposition
+// so it can be restored afterwards rather than permanently clobbered.
+fn install_alarm_handler() -> io::Result<sigaction> {
+    let mut sa: sigaction = unsafe { mem::zeroed() };
+
+    sa.sa_sigaction = handle_alarm as usize as sighandler_t;
+    // Deliberately leave SA_RESTART clear so the blocked `fcntl` returns with
+    // `EINTR` when the timer fires.
+    sa.sa_flags = 0;
+
+    let mut old: sigaction = unsafe { mem::zeroed() };
+
+    if unsafe { sigaction(SIGALRM, &sa, &mut old) } < 0 {
+        return Err(from_raw_os_error(errno() as c_int));
+    }
+
+    Ok(old)
+}
+
+fn restore_alarm_handler(old: &sigaction) {
+    unsafe { sigaction(SIGALRM, old, ::std::ptr::null_mut()); }
+}
+
+fn arm_timer(dur: Duration) -> io::Result<()> {
+    let tv_sec = dur.as_secs() as _;
+    let mut tv_usec = (dur.subsec_nanos() / 1_000) as _;
+
+    // A zero `it_value` disarms the timer; sub-microsecond durations would
+    // round down to that, so floor it at a single tick.
+    if tv_sec == 0 && tv_usec == 0 {
+        tv_usec = 1;
+    }
+
+    let it = itimerval {
+        // Re-fire every 10ms after the initial expiry. This closes the
+        // lost-wakeup race: if the first `SIGALRM` is delivered before the
+        // blocking syscall is entered (and so fails to interrupt it), a
+        // subsequent one will.
+        it_interval: timeval { tv_sec: 0, tv_usec: 10_000 },
+        it_value: timeval { tv_sec: tv_sec, tv_usec: tv_usec },
+    };
+
+    if unsafe { libc::setitimer(ITIMER_REAL, &it, ::std::ptr::null_mut()) } < 0 {
+        return Err(from_raw_os_error(errno() as c_int));
+    }
+
+    Ok(())
+}
+
+fn disarm_timer() -> io::Result<()> {
+    let it: itimerval = unsafe { mem::zeroed() };
+
+    if unsafe { libc::setitimer(ITIMER_REAL, &it, ::std::ptr::null_mut()) } < 0 {
+        return Err(from_raw_os_error(errno() as c_int));
+    }
+
+    Ok(())
+}
+
 fn from_raw_os_error(err: i32) -> io::Error {
     io::Error::from_raw_os_error(err)
 }
@@ -0,0 +1,418 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use std::{io, mem, ptr};
+use std::io::Read;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use libc::pid_t;
+use {LockMode, LockStyle};
+
+/// Result of a `LockFileEx` attempt.
+pub enum LockOutcome {
+    /// The byte-range lock was taken.
+    Acquired,
+    /// Another process already holds the lock.
+    Conflict,
+    /// The bounded wait elapsed first. Unused here: `LockFileEx` has no
+    /// bounded-wait form, so a timeout degrades to a blocking wait.
+    TimedOut,
+}
+
+// Minimal Win32 surface. We only need a handful of calls and, like the
+// `ffi_*` modules on the POSIX side, it is cheaper to declare them by hand
+// than to pull in a heavyweight binding crate.
+
+type HANDLE = *mut c_void;
+type BOOL = i32;
+type DWORD = u32;
+type WORD = u16;
+type LPVOID = *mut c_void;
+type LPDWORD = *mut DWORD;
+type LPCWSTR = *const u16;
+#[allow(non_camel_case_types)]
+type c_void = ::std::os::raw::c_void;
+
+const INVALID_HANDLE_VALUE: HANDLE = !0isize as HANDLE;
+
+const GENERIC_READ: DWORD = 0x8000_0000;
+const GENERIC_WRITE: DWORD = 0x4000_0000;
+
+const FILE_SHARE_READ: DWORD = 0x0000_0001;
+const FILE_SHARE_WRITE: DWORD = 0x0000_0002;
+
+const CREATE_ALWAYS: DWORD = 2;
+const OPEN_ALWAYS: DWORD = 4;
+const OPEN_EXISTING: DWORD = 3;
+
+const FILE_ATTRIBUTE_NORMAL: DWORD = 0x0000_0080;
+const FILE_FLAG_WRITE_THROUGH: DWORD = 0x8000_0000;
+
+const LOCKFILE_FAIL_IMMEDIATELY: DWORD = 0x0000_0001;
+const LOCKFILE_EXCLUSIVE_LOCK: DWORD = 0x0000_0002;
+
+const ERROR_LOCK_VIOLATION: DWORD = 33;
+
+const FILE_BEGIN: DWORD = 0;
+
+const SYNCHRONIZE: DWORD = 0x0010_0000;
+const PROCESS_QUERY_LIMITED_INFORMATION: DWORD = 0x1000;
+const WAIT_TIMEOUT: DWORD = 0x0000_0102;
+
+#[repr(C)]
+struct FILETIME {
+    dwLowDateTime: DWORD,
+    dwHighDateTime: DWORD,
+}
+
+#[repr(C)]
+struct OVERLAPPED {
+    Internal: usize,
+    InternalHigh: usize,
+    Offset: DWORD,
+    OffsetHigh: DWORD,
+    hEvent: HANDLE,
+}
+
+#[repr(C)]
+struct BY_HANDLE_FILE_INFORMATION {
+    dwFileAttributes: DWORD,
+    ftCreationTime: [DWORD; 2],
+    ftLastAccessTime: [DWORD; 2],
+    ftLastWriteTime: [DWORD; 2],
+    dwVolumeSerialNumber: DWORD,
+    nFileSizeHigh: DWORD,
+    nFileSizeLow: DWORD,
+    nNumberOfLinks: DWORD,
+    nFileIndexHigh: DWORD,
+    nFileIndexLow: DWORD,
+}
+
+extern "system" {
+    fn CreateFileW(lpFileName: LPCWSTR,
+                   dwDesiredAccess: DWORD,
+                   dwShareMode: DWORD,
+                   lpSecurityAttributes: LPVOID,
+                   dwCreationDisposition: DWORD,
+                   dwFlagsAndAttributes: DWORD,
+                   hTemplateFile: HANDLE) -> HANDLE;
+    fn LockFileEx(hFile: HANDLE,
+                  dwFlags: DWORD,
+                  dwReserved: DWORD,
+                  nNumberOfBytesToLockLow: DWORD,
+                  nNumberOfBytesToLockHigh: DWORD,
+                  lpOverlapped: *mut OVERLAPPED) -> BOOL;
+    fn SetEndOfFile(hFile: HANDLE) -> BOOL;
+    fn SetFilePointer(hFile: HANDLE,
+                      lDistanceToMove: i32,
+                      lpDistanceToMoveHigh: *mut i32,
+                      dwMoveMethod: DWORD) -> DWORD;
+    fn WriteFile(hFile: HANDLE,
+                 lpBuffer: LPVOID,
+                 nNumberOfBytesToWrite: DWORD,
+                 lpNumberOfBytesWritten: LPDWORD,
+                 lpOverlapped: *mut OVERLAPPED) -> BOOL;
+    fn ReadFile(hFile: HANDLE,
+                lpBuffer: LPVOID,
+                nNumberOfBytesToRead: DWORD,
+                lpNumberOfBytesRead: LPDWORD,
+                lpOverlapped: *mut OVERLAPPED) -> BOOL;
+    fn GetFileInformationByHandle(hFile: HANDLE,
+                                  lpFileInformation: *mut BY_HANDLE_FILE_INFORMATION) -> BOOL;
+    fn CloseHandle(hObject: HANDLE) -> BOOL;
+    fn GetLastError() -> DWORD;
+    fn OpenProcess(dwDesiredAccess: DWORD,
+                   bInheritHandle: BOOL,
+                   dwProcessId: DWORD) -> HANDLE;
+    fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: DWORD) -> DWORD;
+    fn GetProcessTimes(hProcess: HANDLE,
+                       lpCreationTime: *mut FILETIME,
+                       lpExitTime: *mut FILETIME,
+                       lpKernelTime: *mut FILETIME,
+                       lpUserTime: *mut FILETIME) -> BOOL;
+}
+
+fn to_wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
+
+fn last_os_error() -> io::Error {
+    io::Error::from_raw_os_error(unsafe { GetLastError() } as i32)
+}
+
+/// Volume-relative file identity, the Windows analogue of `(st_dev, st_ino)`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FileId {
+    volume: DWORD,
+    index: u64,
+}
+
+pub struct File {
+    handle: HANDLE,
+}
+
+unsafe impl Send for File {}
+
+impl File {
+    pub fn open(path: &Path, create: bool, write: bool, _mode: u32) -> io::Result<File> {
+        let wide = to_wide(path);
+
+        let mut access = GENERIC_READ;
+        if write { access |= GENERIC_WRITE; }
+
+        let disposition = if create {
+            OPEN_ALWAYS
+        } else {
+            OPEN_EXISTING
+        };
+
+        let handle = unsafe {
+            CreateFileW(wide.as_ptr(),
+                        access,
+                        // Exclusivity comes from `LockFileEx`, not the share
+                        // mode, so a contending open still reaches the lock.
+                        FILE_SHARE_READ | FILE_SHARE_WRITE,
+                        ptr::null_mut(),
+                        disposition,
+                        FILE_ATTRIBUTE_NORMAL | FILE_FLAG_WRITE_THROUGH,
+                        ptr::null_mut())
+        };
+
+        debug!("ffi; CreateFileW; ok={}", handle != INVALID_HANDLE_VALUE);
+
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(last_os_error());
+        }
+
+        Ok(File { handle: handle })
+    }
+
+    pub fn truncate(&mut self) -> io::Result<()> {
+        unsafe {
+            SetFilePointer(self.handle, 0, ptr::null_mut(), FILE_BEGIN);
+
+            if SetEndOfFile(self.handle) == 0 {
+                return Err(last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn lock(&mut self, mode: LockMode, _style: LockStyle) -> io::Result<LockOutcome> {
+        // Windows has a single mandatory locking primitive; `LockStyle` only
+        // distinguishes the two POSIX advisory-lock flavours.
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+
+        // `LockFileEx` has no bounded-wait form, so a timeout is treated as a
+        // plain blocking wait here.
+        let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+        if let LockMode::NonBlocking = mode {
+            flags |= LOCKFILE_FAIL_IMMEDIATELY;
+        }
+
+        let ret = unsafe {
+            LockFileEx(self.handle, flags, 0, 1, 0, &mut overlapped)
+        };
+
+        debug!("ffi; LockFileEx; ret={}", ret);
+
+        if ret == 0 {
+            // With `LOCKFILE_FAIL_IMMEDIATELY` on a synchronous handle the
+            // contended case always surfaces as `ERROR_LOCK_VIOLATION`;
+            // `ERROR_IO_PENDING` only arises on asynchronous handles, which we
+            // never open.
+            return match unsafe { GetLastError() } {
+                ERROR_LOCK_VIOLATION => Ok(LockOutcome::Conflict),
+                err => Err(io::Error::from_raw_os_error(err as i32)),
+            };
+        }
+
+        Ok(LockOutcome::Acquired)
+    }
+
+    pub fn check(&mut self, _style: LockStyle) -> io::Result<pid_t> {
+        // There is no `F_GETLK` equivalent on Windows: try to take the lock
+        // non-blockingly and, if it is already held, read the owning pid back
+        // out of the file contents.
+        if let LockOutcome::Acquired = try!(self.lock(LockMode::NonBlocking, LockStyle::Fcntl)) {
+            return Ok(0);
+        }
+
+        self.read_pid()
+    }
+
+    pub fn write(&mut self, pid: pid_t) -> io::Result<()> {
+        // The start time is recorded on a second line so that a later
+        // reclamation can tell a stale pidfile apart from a recycled pid. The
+        // process creation `FILETIME` is already an absolute, reboot-stable
+        // instant, so the boot-relative third line mirrors it.
+        let start = process_start_time(pid).unwrap_or(0);
+        let created = process_created(pid).unwrap_or(0);
+        let buf = format!("{}\n{}\n{}\n", pid, start, created).into_bytes();
+
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let mut written: DWORD = 0;
+
+            let ret = unsafe {
+                WriteFile(self.handle,
+                          buf.as_ptr().offset(pos as isize) as LPVOID,
+                          (buf.len() - pos) as DWORD,
+                          &mut written,
+                          ptr::null_mut())
+            };
+
+            if ret == 0 {
+                return Err(last_os_error());
+            }
+
+            pos += written as usize;
+        }
+
+        Ok(())
+    }
+
+    /// Identifies the open handle against the volume serial number and file
+    /// index, which together play the role that the inode does on POSIX.
+    pub fn file_id(&self) -> io::Result<FileId> {
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+
+        if unsafe { GetFileInformationByHandle(self.handle, &mut info) } == 0 {
+            return Err(last_os_error());
+        }
+
+        Ok(FileId {
+            volume: info.dwVolumeSerialNumber,
+            index: ((info.nFileIndexHigh as u64) << 32) | info.nFileIndexLow as u64,
+        })
+    }
+
+    /// Returns `true` when `path` still refers to the same file as this open
+    /// handle, i.e. the pidfile has not been unlinked and recreated.
+    pub fn is_current(&self, path: &Path) -> io::Result<bool> {
+        let current = try!(self.file_id());
+        let other = try!(File::open(path, false, false, 0));
+        Ok(current == try!(other.file_id()))
+    }
+
+    pub fn recorded_owner(&mut self) -> io::Result<Option<Record>> {
+        let s = try!(self.read_contents());
+        let mut lines = s.lines();
+
+        let pid = match lines.next().and_then(|l| l.trim().parse().ok()) {
+            Some(pid) if pid > 0 => pid,
+            _ => return Ok(None),
+        };
+
+        // Line 2 is the raw start time, kept on disk but not needed here;
+        // line 3 is the absolute instant we compare on.
+        let _offset = lines.next();
+        let created = lines.next().and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+
+        Ok(Some(Record { pid: pid, created: created }))
+    }
+
+    fn read_contents(&mut self) -> io::Result<String> {
+        unsafe { SetFilePointer(self.handle, 0, ptr::null_mut(), FILE_BEGIN); }
+
+        let mut buf = [0u8; 64];
+        let mut read: DWORD = 0;
+
+        let ret = unsafe {
+            ReadFile(self.handle,
+                     buf.as_mut_ptr() as LPVOID,
+                     buf.len() as DWORD,
+                     &mut read,
+                     ptr::null_mut())
+        };
+
+        if ret == 0 {
+            return Err(last_os_error());
+        }
+
+        let mut s = String::new();
+        try!((&buf[..read as usize]).read_to_string(&mut s));
+
+        Ok(s)
+    }
+
+    fn read_pid(&mut self) -> io::Result<pid_t> {
+        let s = try!(self.read_contents());
+
+        Ok(s.lines().nth(0)
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0))
+    }
+}
+
+pub struct Record {
+    pub pid: pid_t,
+    pub created: u64,
+}
+
+/// Reports whether `pid` names a live process whose absolute start instant
+/// still matches `created`. A mismatch means the pid has been recycled onto an
+/// unrelated process, classically after a reboot.
+pub fn process_is_current(pid: pid_t, created: u64) -> bool {
+    process_exists(pid) && process_created(pid).unwrap_or(0) == created
+}
+
+/// The absolute instant at which `pid` started. On Windows the process
+/// creation `FILETIME` is already a wall-clock timestamp, so this is just the
+/// recorded start time.
+pub fn process_created(pid: pid_t) -> io::Result<u64> {
+    process_start_time(pid)
+}
+
+fn process_exists(pid: pid_t) -> bool {
+    // `GetExitCodeProcess` can't distinguish a live process from one that
+    // legitimately exited with code 259 (`STILL_ACTIVE`). Waiting on the
+    // process handle with a zero timeout is unambiguous: a still-running
+    // process never signals, so `WAIT_TIMEOUT` means alive.
+    let handle = unsafe {
+        OpenProcess(SYNCHRONIZE | PROCESS_QUERY_LIMITED_INFORMATION, 0, pid as DWORD)
+    };
+
+    if handle.is_null() {
+        return false;
+    }
+
+    let alive = unsafe { WaitForSingleObject(handle, 0) } == WAIT_TIMEOUT;
+
+    unsafe { CloseHandle(handle); }
+
+    alive
+}
+
+pub fn process_start_time(pid: pid_t) -> io::Result<u64> {
+    let handle = unsafe {
+        OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid as DWORD)
+    };
+
+    if handle.is_null() {
+        return Err(last_os_error());
+    }
+
+    let mut creation: FILETIME = unsafe { mem::zeroed() };
+    let mut ignored: FILETIME = unsafe { mem::zeroed() };
+
+    let ret = unsafe {
+        GetProcessTimes(handle, &mut creation, &mut ignored, &mut ignored, &mut ignored)
+    };
+
+    unsafe { CloseHandle(handle); }
+
+    if ret == 0 {
+        return Err(last_os_error());
+    }
+
+    Ok(((creation.dwHighDateTime as u64) << 32) | creation.dwLowDateTime as u64)
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        debug!("closing file");
+        unsafe { CloseHandle(self.handle); }
+    }
+}
@@ -4,19 +4,23 @@
 #![crate_name = "pidfile"]
 
 extern crate libc;
+#[cfg(unix)]
 extern crate nix;
+#[cfg(test)]
 extern crate tempdir;
 
 #[macro_use]
 extern crate log;
 
-use file::File;
+use file::{File, LockOutcome};
 use libc::pid_t;
+#[cfg(unix)]
 use nix::sys::stat::stat;
 use std::{fmt, io};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 #[path = "ffi_darwin.rs"]
@@ -34,28 +38,114 @@ mod ffi;
 #[path = "file_posix.rs"]
 mod file;
 
+#[cfg(windows)]
+#[path = "file_windows.rs"]
+mod file;
+
+// Fallback for targets with no usable file-locking API. Downstream crates
+// that merely depend on pidfile still build for e.g. `wasm32-unknown-unknown`;
+// the operations return `io::ErrorKind::Unsupported` at runtime. The
+// `unsupported` CI job in `.github/workflows/ci.yml` builds that target so the
+// fallback cannot regress unnoticed.
+#[cfg(not(any(unix, windows)))]
+#[path = "file.rs"]
+mod file;
+
 pub fn at<S: AsRef<Path> + ?Sized>(path: &S) -> Request {
     Request {
         pid: pid(),
         path: PathBuf::from(path.as_ref()),
         perm: 0o644,
+        mode: LockMode::NonBlocking,
+        style: LockStyle::Fcntl,
     }
 }
 
+/// Which kind of advisory lock to take.
+///
+/// The two styles differ in their inheritance semantics, which matters for
+/// callers that daemonize:
+///
+/// * `Fcntl` uses POSIX `fcntl(F_SETLK)` record locks. These are owned by the
+///   process, not the descriptor: closing *any* descriptor to the file drops
+///   the lock for the whole process, and the lock is not shared across a
+///   `fork`.
+/// * `Flock` uses BSD `flock(2)` locks. These are owned by the open file
+///   description: they survive `dup` and are shared with a forked child, and
+///   are only released when the last such descriptor is closed.
+#[derive(Clone, Copy)]
+pub enum LockStyle {
+    Fcntl,
+    Flock,
+}
+
+/// How `Request::lock` should behave when the lock is already held.
+enum LockMode {
+    /// Fail immediately with `LockError::conflict()` (the default).
+    NonBlocking,
+    /// Wait until the holder releases the lock.
+    Blocking,
+    /// Wait until the holder releases the lock or the duration elapses,
+    /// whichever comes first.
+    Timeout(Duration),
+}
+
 pub struct Request {
     pid: pid_t,
     path: PathBuf,
     perm: u32,
+    mode: LockMode,
+    style: LockStyle,
 }
 
 impl Request {
+    /// Wait until the lock can be acquired instead of failing on conflict.
+    ///
+    /// On the `timeout` path this installs a process-global `SIGALRM` handler
+    /// and arms the shared `ITIMER_REAL`, so `timeout`/`blocking` acquisitions
+    /// must not run concurrently from multiple threads and will transiently
+    /// displace any `SIGALRM` the caller relies on. `blocking` itself does not
+    /// touch the timer, but is documented alongside `timeout` for clarity.
+    pub fn blocking(mut self) -> Request {
+        self.mode = LockMode::Blocking;
+        self
+    }
+
+    /// Wait up to `timeout` for the lock, then give up with
+    /// `LockError::timed_out()`.
+    ///
+    /// This arms a process-wide `SIGALRM`/`ITIMER_REAL` to interrupt the
+    /// blocking `fcntl`, so it is **not** safe to call from more than one
+    /// thread at a time, and it briefly replaces the caller's `SIGALRM`
+    /// disposition (restored when the wait ends).
+    ///
+    /// On Windows `LockFileEx` has no bounded wait: the duration is not
+    /// applied and the call waits until the lock is free.
+    pub fn timeout(mut self, timeout: Duration) -> Request {
+        self.mode = LockMode::Timeout(timeout);
+        self
+    }
+
+    /// Select the locking primitive. Defaults to `LockStyle::Fcntl`.
+    pub fn lock_style(mut self, style: LockStyle) -> Request {
+        self.style = style;
+        self
+    }
+
     pub fn lock(self) -> LockResult<Lock> {
         let res = File::open(&*self.path, true, true, self.perm);
         let mut f = try!(res.map_err(LockError::io_error));
 
-        if !try!(f.lock().map_err(LockError::io_error)) {
-            debug!("lock not acquired; conflict");
-            return Err(LockError::conflict());
+        match try!(f.lock(self.mode, self.style).map_err(LockError::io_error)) {
+            LockOutcome::Acquired => {}
+            LockOutcome::Conflict => {
+                debug!("lock not acquired; conflict");
+                return Err(LockError::conflict());
+            }
+            LockOutcome::TimedOut => {
+                debug!("lock not acquired; timed out");
+                return Err(LockError::timed_out());
+            }
         }
 
         debug!("lock acquired");
@@ -65,8 +155,48 @@ impl Request {
 
         debug!("lockfile written");
 
+        let created = file::process_created(self.pid).unwrap_or(0);
+
         return Ok(Lock {
-            pidfile: Pidfile { pid: self.pid as u32 },
+            pidfile: Pidfile { pid: self.pid as u32, created: created },
+            handle: f,
+            path: self.path
+        })
+    }
+
+    /// Like `lock`, but reclaims a pidfile left behind by a process that no
+    /// longer exists (or whose pid has since been recycled onto an unrelated
+    /// process).
+    ///
+    /// Reclamation of a *dead* owner happens on the normal acquire path rather
+    /// than in a special branch: both `fcntl` record locks and `flock(2)`
+    /// locks are released by the kernel when the holding process exits, so the
+    /// acquire below simply succeeds and the stale contents are overwritten.
+    /// A surviving `Conflict` therefore means a *live* process holds the lock;
+    /// truncating the file in that case would clobber a pidfile another process
+    /// is actively using, so the conflict is reported as-is.
+    pub fn lock_or_reclaim(self) -> LockResult<Lock> {
+        let mut f = try!(File::open(&*self.path, true, true, self.perm)
+            .map_err(LockError::io_error));
+
+        match try!(f.lock(self.mode, self.style).map_err(LockError::io_error)) {
+            LockOutcome::Acquired => {}
+            LockOutcome::TimedOut => return Err(LockError::timed_out()),
+            LockOutcome::Conflict => {
+                debug!("lock not acquired; live owner");
+                return Err(LockError::conflict());
+            }
+        }
+
+        try!(f.truncate().map_err(LockError::io_error));
+        try!(f.write(self.pid).map_err(LockError::io_error));
+
+        debug!("lockfile written");
+
+        let created = file::process_created(self.pid).unwrap_or(0);
+
+        Ok(Lock {
+            pidfile: Pidfile { pid: self.pid as u32, created: created },
             handle: f,
             path: self.path
         })
@@ -90,7 +220,7 @@ impl Request {
             }
         };
 
-        let pid = try!(f.check());
+        let pid = try!(f.check(self.style));
 
         if pid == 0 {
             debug!("no lock acquired -- file exists");
@@ -99,7 +229,11 @@ impl Request {
 
         debug!("lock acquired; pid={}", pid);
 
-        Ok(Some(Pidfile { pid: pid as u32 }))
+        let created = try!(f.recorded_owner())
+            .map(|r| r.created)
+            .unwrap_or(0);
+
+        Ok(Some(Pidfile { pid: pid as u32, created: created }))
     }
 }
 
@@ -107,13 +241,30 @@ impl Request {
 /// active lock.
 #[derive(Clone, Debug, Copy)]
 pub struct Pidfile {
-    pid: u32
+    pid: u32,
+    // Absolute start instant of the owning process, recorded on the third line
+    // of the file. On Linux it is seconds since the epoch, reconstructed from
+    // the system boot time and the process's boot offset; on BSD/Darwin it is
+    // seconds since the epoch from `kinfo_proc`; on Windows it is the raw
+    // process-creation `FILETIME` (100ns units). Together with `pid` it forms
+    // an identity that survives a reboot: the pid may be reused afterwards, but
+    // the new process starts at a different wall-clock instant.
+    created: u64,
 }
 
 impl Pidfile {
     pub fn pid(&self) -> u32 {
         self.pid
     }
+
+    /// Reports whether the process that created this pidfile is still running.
+    ///
+    /// This checks more than the bare pid: it compares the recorded absolute
+    /// start instant against the live process, so a pidfile left behind across
+    /// a reboot whose pid has been recycled is correctly reported as not live.
+    pub fn is_live(&self) -> bool {
+        file::process_is_current(self.pid as pid_t, self.created)
+    }
 }
 
 pub struct Lock {
@@ -142,6 +293,11 @@ impl Lock {
         //      - otherwise, return Err(None)
         //
 
+        self.compare_identity()
+    }
+
+    #[cfg(unix)]
+    fn compare_identity(&self) -> Result<(), Option<u32>> {
         let current_stat = match self.handle.stat() {
             Err(_) => return Err(self.read_pid()),
             Ok(stat) => stat
@@ -149,13 +305,42 @@ impl Lock {
 
         let path_stat = try!(stat(&*self.path).map_err(|_| None));
 
-        if current_stat.st_ino == path_stat.st_ino {
-            Ok(())
-        } else {
-            Err(self.read_pid())
+        if current_stat.st_ino != path_stat.st_ino {
+            return Err(self.read_pid());
+        }
+
+        // A process can only write its pidfile after it has started, so the
+        // file's last-modified time cannot predate the recorded start instant.
+        // The nanosecond-resolution `st_mtime` fields let us reject a leftover
+        // file whose owning pid has been recycled onto a newer process: such a
+        // file was written before that process existed.
+        let mtime = current_stat.st_mtime as i64 * 1_000_000_000
+            + current_stat.st_mtime_nsec as i64;
+        let created = self.pidfile.created as i64 * 1_000_000_000;
+
+        // One-second slack absorbs the whole-second resolution of the stored
+        // boot-relative instant.
+        if created != 0 && mtime + 1_000_000_000 < created {
+            return Err(self.read_pid());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn compare_identity(&self) -> Result<(), Option<u32>> {
+        match self.handle.is_current(&*self.path) {
+            Ok(true) => Ok(()),
+            _ => Err(self.read_pid()),
         }
     }
 
+    #[cfg(not(any(unix, windows)))]
+    fn compare_identity(&self) -> Result<(), Option<u32>> {
+        // No locking backend on this target; nothing to compare against.
+        Err(self.read_pid())
+    }
+
     fn read_pid(&self) -> Option<u32> {
         if let Ok(mut f) = std::fs::File::open(&self.path) {
             let mut s = String::new();
@@ -173,6 +358,7 @@ impl Lock {
 #[derive(Debug)]
 pub struct LockError {
     pub conflict: bool,
+    pub timed_out: bool,
     pub io: Option<io::Error>,
 }
 
@@ -180,6 +366,15 @@ impl LockError {
     fn conflict() -> LockError {
         LockError {
             conflict: true,
+            timed_out: false,
+            io: None
+        }
+    }
+
+    fn timed_out() -> LockError {
+        LockError {
+            conflict: false,
+            timed_out: true,
             io: None
         }
     }
@@ -187,6 +382,7 @@ impl LockError {
     fn io_error(err: io::Error) -> LockError {
         LockError {
             conflict: false,
+            timed_out: false,
             io: Some(err)
         }
     }
@@ -200,13 +396,24 @@ impl fmt::Debug for Lock {
 
 pub type LockResult<T> = Result<T, LockError>;
 
+#[cfg(any(unix, windows))]
 fn pid() -> pid_t {
     unsafe { libc::getpid() }
 }
 
+#[cfg(not(any(unix, windows)))]
+fn pid() -> pid_t {
+    // No process-id notion to speak of on this target.
+    0
+}
+
 #[cfg(test)]
 mod tests {
 
+    // NOTE: `fcntl` record locks are owned per-process, so the three threads
+    // below all share one lock owner and all succeed. This exercises the happy
+    // path but asserts *nothing* about mutual exclusion; see
+    // `mutual_exclusion_across_processes` for that.
     #[test]
     fn main() {
 		use std::thread;
@@ -241,4 +448,239 @@ mod tests {
 
 
     }
+
+    // The locking behaviour can only be exercised across process boundaries
+    // (`fcntl` record locks are per-process, so threads never contend), so the
+    // tests below fork a child holder and observe the conflict from the parent.
+    #[cfg(unix)]
+    mod support {
+        use std::path::{Path, PathBuf};
+        use std::ptr;
+        use std::thread;
+        use std::time::Duration;
+        use at;
+
+        /// Acquire the pidfile in a forked child and keep holding it for
+        /// `hold`, then release and `_exit` (so the child never runs the
+        /// parent's test destructors). Returns the child pid to reap.
+        pub fn hold_in_child(path: &Path, hold: Duration) -> ::libc::pid_t {
+            let path: PathBuf = path.to_path_buf();
+            let pid = unsafe { ::libc::fork() };
+            assert!(pid >= 0, "fork failed");
+
+            if pid == 0 {
+                let lock = at(&path).lock().ok();
+                thread::sleep(hold);
+                drop(lock);
+                unsafe { ::libc::_exit(0); }
+            }
+
+            pid
+        }
+
+        pub fn reap(pid: ::libc::pid_t) {
+            unsafe {
+                ::libc::kill(pid, ::libc::SIGKILL);
+                ::libc::waitpid(pid, ptr::null_mut(), 0);
+            }
+        }
+
+        /// A pid guaranteed to be dead: fork a child that exits immediately and
+        /// reap it.
+        pub fn dead_pid() -> ::libc::pid_t {
+            let pid = unsafe { ::libc::fork() };
+            assert!(pid >= 0, "fork failed");
+
+            if pid == 0 {
+                unsafe { ::libc::_exit(0); }
+            }
+
+            unsafe { ::libc::waitpid(pid, ptr::null_mut(), 0); }
+            pid
+        }
+
+        /// Write a pidfile naming `pid` with placeholder start/created lines,
+        /// as a stale file left behind by a previous run.
+        pub fn write_stale(path: &Path, pid: ::libc::pid_t) {
+            use std::io::Write;
+
+            let mut f = ::std::fs::File::create(path).expect("create pidfile");
+            write!(f, "{}\n0\n0\n", pid).expect("write pidfile");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mutual_exclusion_across_processes() {
+        use std::thread;
+        use std::time::Duration;
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        let child = support::hold_in_child(&path, Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(200));
+
+        let res = at(&path).lock();
+        assert!(res.is_err(), "lock must be refused while another process holds it");
+        assert!(res.err().unwrap().conflict);
+
+        support::reap(child);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_elapses_against_held_lock() {
+        use std::thread;
+        use std::time::Duration;
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        let child = support::hold_in_child(&path, Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(200));
+
+        let res = at(&path).timeout(Duration::from_millis(300)).lock();
+        assert!(res.is_err());
+
+        let err = res.err().unwrap();
+        assert!(err.timed_out, "expected a timeout");
+        assert!(!err.conflict, "a timeout must not also report a conflict");
+
+        support::reap(child);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zero_timeout_fails_fast() {
+        use std::thread;
+        use std::time::Duration;
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        let child = support::hold_in_child(&path, Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(200));
+
+        // A zero duration must fail fast rather than disarm the timer and block
+        // forever.
+        let res = at(&path).timeout(Duration::from_millis(0)).lock();
+        assert!(res.err().expect("must not block").timed_out);
+
+        support::reap(child);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn blocking_waits_then_acquires() {
+        use std::thread;
+        use std::time::Duration;
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        // The child releases after a short hold; a blocking acquire must wait
+        // it out and then succeed.
+        let child = support::hold_in_child(&path, Duration::from_millis(400));
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(at(&path).blocking().lock().is_ok());
+
+        support::reap(child);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_or_reclaim_reclaims_dead_owner() {
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        // Leave a pidfile naming a dead pid. Its lock is long gone, so the
+        // acquire simply succeeds and overwrites the stale contents.
+        support::write_stale(&path, support::dead_pid());
+
+        let lock = at(&path).lock_or_reclaim().expect("stale pidfile reclaimed");
+        assert_eq!(lock.pidfile().pid(), unsafe { ::libc::getpid() } as u32);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_or_reclaim_keeps_live_owner() {
+        use std::thread;
+        use std::time::Duration;
+        use at;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        let child = support::hold_in_child(&path, Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(200));
+
+        // A live holder must never be clobbered, even via the reclaim path.
+        let res = at(&path).lock_or_reclaim();
+        assert!(res.err().expect("live owner must win").conflict);
+
+        support::reap(child);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn is_live_rejects_recycled_pid() {
+        use at;
+        use file;
+        use tempdir::TempDir;
+
+        let me = unsafe { ::libc::getpid() };
+
+        // A dead pid is never the current owner.
+        assert!(!file::process_is_current(support::dead_pid(), 0));
+
+        // Our own pid paired with a start instant that cannot be ours (the
+        // boot-relative reboot + recycle case) is rejected...
+        assert!(!file::process_is_current(me, 1));
+
+        // ...but the correctly reconstructed instant is accepted.
+        let created = file::process_created(me).unwrap_or(0);
+        assert!(file::process_is_current(me, created));
+
+        // And a freshly taken lock reports its own owner as live.
+        let dir = TempDir::new("").expect("create temp dir");
+        let lock = at(&dir.path().join("pidfile")).lock().expect("acquire");
+        assert!(lock.pidfile().is_live());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn flock_style_locks_and_reports_owner() {
+        use at;
+        use LockStyle;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("").expect("create temp dir");
+        let path = dir.path().join("pidfile");
+
+        let held = at(&path).lock_style(LockStyle::Flock).lock().expect("acquire flock");
+
+        // `flock` has no `F_GETLK`, so a contended `check()` falls back to the
+        // pid recorded in the file. A second open file description conflicts
+        // with the held lock even within this process.
+        let found = at(&path).lock_style(LockStyle::Flock).check().expect("check");
+        assert_eq!(found.map(|p| p.pid()), Some(held.pidfile().pid()));
+
+        // Once released, `check()` reports no owner.
+        drop(held);
+        assert!(at(&path).lock_style(LockStyle::Flock).check().expect("check").is_none());
+    }
 }